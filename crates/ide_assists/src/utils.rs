@@ -2,8 +2,7 @@
 
 pub(crate) mod suggest_name;
 mod gen_trait_fn_body;
-
-use std::ops;
+mod snippet_builder;
 
 use hir::HasSource;
 use ide_db::{helpers::SnippetCap, path_transform::PathTransform, RootDatabase};
@@ -16,7 +15,7 @@ use syntax::{
         edit_in_place::AttrsOwnerEdit,
         make, HasArgList, HasAttrs, HasGenericParams, HasName, HasTypeBounds, Whitespace,
     },
-    ted, AstNode, AstToken, Direction, SmolStr, SourceFile,
+    ted, AstNode, AstToken, Direction, SmolStr, SourceFile, SyntaxKind,
     SyntaxKind::*,
     SyntaxNode, TextRange, TextSize, T,
 };
@@ -24,6 +23,7 @@ use syntax::{
 use crate::assist_context::{AssistBuilder, AssistContext};
 
 pub(crate) use gen_trait_fn_body::gen_trait_fn_body;
+pub(crate) use snippet_builder::Builder as SnippetBuilder;
 
 pub(crate) fn unwrap_trivial_block(block_expr: ast::BlockExpr) -> ast::Expr {
     extract_trivial_expression(&block_expr)
@@ -65,24 +65,66 @@ pub fn extract_trivial_expression(block_expr: &ast::BlockExpr) -> Option<ast::Ex
     None
 }
 
-/// This is a method with a heuristics to support test methods annotated with custom test annotations, such as
-/// `#[test_case(...)]`, `#[tokio::test]` and similar.
-/// Also a regular `#[test]` annotation is supported.
-///
-/// It may produce false positives, for example, `#[wasm_bindgen_test]` requires a different command to run the test,
-/// but it's better than not to have the runnables for the tests at all.
-pub fn test_related_attribute(fn_def: &ast::Fn) -> Option<ast::Attr> {
+/// How a test-like attribute found by [`test_related_attribute`] should
+/// actually be run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestRunner {
+    /// A plain `#[test]`, runnable with `cargo test`.
+    Plain,
+    /// An async test driven by a runtime's own attribute, e.g. `#[tokio::test]`.
+    AsyncRuntime,
+    /// A parameterized test, e.g. `#[test_case(...)]`.
+    Parameterized,
+    /// Runs in a browser/wasm environment via `wasm-pack test`, not `cargo test`.
+    WasmBindgen,
+    /// Property-based testing frameworks (`#[proptest]`, `#[quickcheck]`),
+    /// which wrap the function body rather than running it directly.
+    PropertyBased,
+    /// Looks test-like (the attribute's path starts or ends with `test`) but
+    /// we don't otherwise recognize it, so we don't know how to run it.
+    Unknown,
+}
+
+/// A `#[...]`-annotated `fn` recognized as some kind of test.
+#[derive(Debug, Clone)]
+pub struct TestAttr {
+    /// The attribute that made us recognize `fn_def` as a test.
+    pub attr: ast::Attr,
+    pub runner: TestRunner,
+}
+
+/// Classifies a `#[...]`-annotated `fn` into a known test-framework kind,
+/// such as a plain `#[test]`, `#[tokio::test]`, `#[test_case(...)]`, or
+/// `#[wasm_bindgen_test]`, returning the matched attribute together with its
+/// [`TestRunner`] so runnable generation can pick the right invocation
+/// (`cargo test` vs. `wasm-pack test`, etc.) instead of assuming `cargo test`
+/// works for everything whose attribute merely contains `test`.
+pub fn test_related_attribute(fn_def: &ast::Fn) -> Option<TestAttr> {
     fn_def.attrs().find_map(|attr| {
         let path = attr.path()?;
         let text = path.syntax().text().to_string();
-        if text.starts_with("test") || text.ends_with("test") {
-            Some(attr)
-        } else {
-            None
-        }
+        let runner = classify_test_attribute(&text)?;
+        Some(TestAttr { attr, runner })
     })
 }
 
+fn classify_test_attribute(path_text: &str) -> Option<TestRunner> {
+    match path_text {
+        "test" => return Some(TestRunner::Plain),
+        "tokio::test" | "async_std::test" | "actix_rt::test" => {
+            return Some(TestRunner::AsyncRuntime)
+        }
+        "test_case" => return Some(TestRunner::Parameterized),
+        "wasm_bindgen_test" => return Some(TestRunner::WasmBindgen),
+        "proptest" | "quickcheck" => return Some(TestRunner::PropertyBased),
+        _ => {}
+    }
+    if path_text.starts_with("test") || path_text.ends_with("test") {
+        return Some(TestRunner::Unknown);
+    }
+    None
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum DefaultMethods {
     Only,
@@ -170,41 +212,22 @@ pub fn add_trait_assoc_items_to_impl(
     (res, first_item.unwrap())
 }
 
-#[derive(Clone, Copy, Debug)]
-pub(crate) enum Cursor<'a> {
-    Replace(&'a SyntaxNode),
-    Before(&'a SyntaxNode),
-}
-
-impl<'a> Cursor<'a> {
-    fn node(self) -> &'a SyntaxNode {
-        match self {
-            Cursor::Replace(node) | Cursor::Before(node) => node,
-        }
-    }
-}
-
-pub(crate) fn render_snippet(_cap: SnippetCap, node: &SyntaxNode, cursor: Cursor) -> String {
-    assert!(cursor.node().ancestors().any(|it| it == *node));
-    let range = cursor.node().text_range() - node.text_range().start();
-    let range: ops::Range<usize> = range.into();
-
-    let mut placeholder = cursor.node().to_string();
-    escape(&mut placeholder);
-    let tab_stop = match cursor {
-        Cursor::Replace(placeholder) => format!("${{0:{}}}", placeholder),
-        Cursor::Before(placeholder) => format!("$0{}", placeholder),
-    };
-
-    let mut buf = node.to_string();
-    buf.replace_range(range, &tab_stop);
-    return buf;
-
-    fn escape(buf: &mut String) {
-        stdx::replace(buf, '{', r"\{");
-        stdx::replace(buf, '}', r"\}");
-        stdx::replace(buf, '$', r"\$");
-    }
+/// Renders `node` as an LSP snippet string, recording tabstops/placeholders
+/// on it via `build` before serializing.
+///
+/// This replaces the old single-stop `Cursor`-based API: `build` can record
+/// as many stops as the caller needs (e.g. a generated function signature
+/// with several typed-hole arguments, or a generated impl with both a name
+/// and a body cursor) and [`SnippetBuilder`] assigns sequential indices and
+/// centralizes escaping, so callers never hand-escape `{`, `}`, `$` again.
+pub(crate) fn render_snippet(
+    _cap: SnippetCap,
+    node: &SyntaxNode,
+    build: impl FnOnce(&mut SnippetBuilder),
+) -> String {
+    let mut builder = SnippetBuilder::new();
+    build(&mut builder);
+    builder.finish(node)
 }
 
 pub(crate) fn vis_offset(node: &SyntaxNode) -> TextSize {
@@ -218,23 +241,58 @@ pub(crate) fn invert_boolean_expression(expr: ast::Expr) -> ast::Expr {
     invert_special_case(&expr).unwrap_or_else(|| make::expr_prefix(T![!], expr))
 }
 
+// `&&` binds tighter than `||`, so splicing a `||` expression in as an
+// operand of a freshly built `&&` would silently change how the result
+// associates unless it's parenthesized. A `&&` operand of a `||` is
+// parenthesized as well.
+fn parenthesize_cond(expr: ast::Expr, outer_op: SyntaxKind) -> ast::Expr {
+    match &expr {
+        ast::Expr::BinExpr(bin) => match bin.op_token() {
+            Some(tok) if matches!(tok.kind(), T![&&] | T![||]) && tok.kind() != outer_op => {
+                make::expr_paren(expr)
+            }
+            _ => expr,
+        },
+        _ => expr,
+    }
+}
+
 fn invert_special_case(expr: &ast::Expr) -> Option<ast::Expr> {
     match expr {
         ast::Expr::BinExpr(bin) => {
-            let bin = bin.clone_for_update();
             let op_token = bin.op_token()?;
-            let rev_token = match op_token.kind() {
-                T![==] => T![!=],
-                T![!=] => T![==],
-                T![<] => T![>=],
-                T![<=] => T![>],
-                T![>] => T![<=],
-                T![>=] => T![<],
+            match op_token.kind() {
+                // Apply De Morgan's law, distributing the negation into both
+                // operands and recursing, rather than just wrapping the
+                // whole `&&`/`||` expression in `!( … )`.
+                T![&&] | T![||] => {
+                    let inv_op = if op_token.kind() == T![&&] { T![||] } else { T![&&] };
+                    let lhs = invert_boolean_expression(bin.lhs()?);
+                    let rhs = invert_boolean_expression(bin.rhs()?);
+                    Some(make::expr_bin_op(
+                        parenthesize_cond(lhs, inv_op),
+                        inv_op,
+                        parenthesize_cond(rhs, inv_op),
+                    ))
+                }
+                T![==] | T![!=] | T![<] | T![<=] | T![>] | T![>=] => {
+                    let bin = bin.clone_for_update();
+                    let op_token = bin.op_token()?;
+                    let rev_token = match op_token.kind() {
+                        T![==] => T![!=],
+                        T![!=] => T![==],
+                        T![<] => T![>=],
+                        T![<=] => T![>],
+                        T![>] => T![<=],
+                        T![>=] => T![<],
+                        _ => unreachable!(),
+                    };
+                    ted::replace(op_token, make::token(rev_token));
+                    Some(bin.into())
+                }
                 // Parenthesize other expressions before prefixing `!`
-                _ => return Some(make::expr_prefix(T![!], make::expr_paren(expr.clone()))),
-            };
-            ted::replace(op_token, make::token(rev_token));
-            Some(bin.into())
+                _ => Some(make::expr_prefix(T![!], make::expr_paren(expr.clone()))),
+            }
         }
         ast::Expr::MethodCallExpr(mce) => {
             let receiver = mce.receiver()?;
@@ -254,6 +312,9 @@ fn invert_special_case(expr: &ast::Expr) -> Option<ast::Expr> {
             ast::Expr::ParenExpr(parexpr) => parexpr.expr(),
             _ => pe.expr(),
         },
+        // Look through parens so e.g. `a && (b || c)` still distributes into
+        // `!a || (!b && !c)` instead of stopping at a leading `!(b || c)`.
+        ast::Expr::ParenExpr(paren) => invert_special_case(&paren.expr()?),
         ast::Expr::Literal(lit) => match lit.kind() {
             ast::LiteralKind::Bool(b) => match b {
                 true => Some(ast::Expr::Literal(make::expr_literal("false"))),
@@ -297,35 +358,33 @@ pub(crate) fn does_nested_pattern(pat: &ast::Pat) -> bool {
 fn calc_depth(pat: &ast::Pat, depth: usize) -> usize {
     match pat {
         ast::Pat::IdentPat(_)
-        | ast::Pat::BoxPat(_)
         | ast::Pat::RestPat(_)
         | ast::Pat::LiteralPat(_)
         | ast::Pat::MacroPat(_)
-        | ast::Pat::OrPat(_)
-        | ast::Pat::ParenPat(_)
         | ast::Pat::PathPat(_)
         | ast::Pat::WildcardPat(_)
         | ast::Pat::RangePat(_)
-        | ast::Pat::RecordPat(_)
-        | ast::Pat::RefPat(_)
-        | ast::Pat::SlicePat(_)
-        | ast::Pat::TuplePat(_)
         | ast::Pat::ConstBlockPat(_) => depth,
 
-        // FIXME: Other patterns may also be nested. Currently it simply supports only `TupleStructPat`
-        ast::Pat::TupleStructPat(pat) => {
-            let mut max_depth = depth;
-            for p in pat.fields() {
-                let d = calc_depth(&p, depth + 1);
-                if d > max_depth {
-                    max_depth = d
-                }
-            }
-            max_depth
-        }
+        ast::Pat::TupleStructPat(pat) => max_child_depth(pat.fields(), depth),
+        ast::Pat::TuplePat(pat) => max_child_depth(pat.fields(), depth),
+        ast::Pat::SlicePat(pat) => max_child_depth(pat.pats(), depth),
+        ast::Pat::OrPat(pat) => max_child_depth(pat.pats(), depth),
+        ast::Pat::RecordPat(pat) => max_child_depth(
+            pat.record_pat_field_list().into_iter().flat_map(|it| it.fields()).filter_map(|f| f.pat()),
+            depth,
+        ),
+
+        ast::Pat::ParenPat(pat) => pat.pat().map_or(depth, |p| calc_depth(&p, depth + 1)),
+        ast::Pat::RefPat(pat) => pat.pat().map_or(depth, |p| calc_depth(&p, depth + 1)),
+        ast::Pat::BoxPat(pat) => pat.pat().map_or(depth, |p| calc_depth(&p, depth + 1)),
     }
 }
 
+fn max_child_depth(pats: impl Iterator<Item = ast::Pat>, depth: usize) -> usize {
+    pats.map(|p| calc_depth(&p, depth + 1)).max().unwrap_or(depth)
+}
+
 // Uses a syntax-driven approach to find any impl blocks for the struct that
 // exist within the module/file
 //
@@ -413,13 +472,14 @@ pub(crate) fn find_impl_block_end(impl_def: ast::Impl, buf: &mut String) -> Opti
 }
 
 // Generates the surrounding `impl Type { <code> }` including type and lifetime
-// parameters
+// parameters. `code` is spliced in verbatim, so it may itself be a snippet
+// produced by `render_snippet`/`SnippetBuilder` with tabstops already baked in.
 pub(crate) fn generate_impl_text(adt: &ast::Adt, code: &str) -> String {
     generate_impl_text_inner(adt, None, code)
 }
 
 // Generates the surrounding `impl <trait> for Type { <code> }` including type
-// and lifetime parameters
+// and lifetime parameters. See `generate_impl_text` re: `code` and snippets.
 pub(crate) fn generate_trait_impl_text(adt: &ast::Adt, trait_text: &str, code: &str) -> String {
     generate_impl_text_inner(adt, Some(trait_text), code)
 }
@@ -485,6 +545,8 @@ fn generate_impl_text_inner(adt: &ast::Adt, trait_text: Option<&str>, code: &str
     buf
 }
 
+// `method` is spliced in verbatim, so like `generate_impl_text` it may be a
+// snippet produced by `render_snippet`/`SnippetBuilder`.
 pub(crate) fn add_method_to_adt(
     builder: &mut AssistBuilder,
     adt: &ast::Adt,
@@ -507,27 +569,72 @@ pub(crate) fn add_method_to_adt(
     builder.insert(start_offset, buf);
 }
 
-pub fn useless_type_special_case(field_name: &str, field_ty: &String) -> Option<(String, String)> {
-    if field_ty == "String" {
-        cov_mark::hit!(useless_type_special_case);
-        return Some(("&str".to_string(), format!("self.{}.as_str()", field_name)));
-    }
-    if let Some(arg) = ty_ctor(field_ty, "Vec") {
-        return Some((format!("&[{}]", arg), format!("self.{}.as_slice()", field_name)));
-    }
-    if let Some(arg) = ty_ctor(field_ty, "Box") {
-        return Some((format!("&{}", arg), format!("self.{}.as_ref()", field_name)));
-    }
-    if let Some(arg) = ty_ctor(field_ty, "Option") {
-        return Some((format!("Option<&{}>", arg), format!("self.{}.as_ref()", field_name)));
+/// Returns the ADT's bare name, but only if it's actually defined in `core`,
+/// `alloc`, or `std` — guarding against a user-defined (or third-party) type
+/// that merely happens to share a name with one of the standard wrappers,
+/// e.g. a local `struct Vec`.
+fn std_wrapper_name(db: &RootDatabase, adt: hir::Adt) -> Option<String> {
+    let krate = adt.module(db).krate();
+    let krate_name = krate.display_name(db)?.to_string();
+    if !matches!(krate_name.as_str(), "core" | "alloc" | "std") {
+        return None;
     }
-    None
+    Some(adt.name(db).to_string())
 }
 
-// FIXME: This should rely on semantic info.
-fn ty_ctor(ty: &String, ctor: &str) -> Option<String> {
-    let res = ty.to_string().strip_prefix(ctor)?.strip_prefix('<')?.strip_suffix('>')?.to_string();
-    Some(res)
+/// If `ty` is one of the standard wrapper types that offer a cheap
+/// shared-reference view of their contents (`String` -> `&str`/`&[u8]`,
+/// `Vec<T>` -> `&[T]`, `Box<T>`/`Arc<T>`/`Rc<T>` -> `&T`, `Cow<'_, T>` ->
+/// `&T` (via deref, since `Cow` doesn't implement `AsRef`), `PathBuf` ->
+/// `&Path`, `OsString` -> `&OsStr`, `CString` -> `&CStr`), or is an
+/// `Option<T>` (-> `Option<&T>`), returns the
+/// resulting reference type(s) together with the expression (reading from
+/// `field_name`) that produces each. Returns an empty `Vec` for any other
+/// type.
+///
+/// This resolves `ty` through `hir` and checks the defining crate rather
+/// than matching on the type's surface syntax, so it recognizes the wrapper
+/// behind a type alias or a fully-qualified path like `std::vec::Vec<T>`,
+/// without misclassifying an unrelated type of the same name.
+pub fn useless_type_special_case(
+    field_name: &str,
+    ty: &hir::Type,
+    db: &RootDatabase,
+) -> Vec<(String, String)> {
+    useless_type_special_case_inner(field_name, ty, db).unwrap_or_default()
+}
+
+fn useless_type_special_case_inner(
+    field_name: &str,
+    ty: &hir::Type,
+    db: &RootDatabase,
+) -> Option<Vec<(String, String)>> {
+    let name = std_wrapper_name(db, ty.as_adt()?)?;
+    let arg = || Some(ty.type_arguments().next()?.display(db).to_string());
+
+    let views = match name.as_str() {
+        "String" => {
+            cov_mark::hit!(useless_type_special_case);
+            vec![
+                ("&str".to_string(), format!("self.{}.as_str()", field_name)),
+                ("&[u8]".to_string(), format!("self.{}.as_bytes()", field_name)),
+            ]
+        }
+        // `Vec<u8>` already gets its `&[u8]` view from the general case below.
+        "Vec" => vec![(format!("&[{}]", arg()?), format!("self.{}.as_slice()", field_name))],
+        "Box" | "Arc" | "Rc" => {
+            vec![(format!("&{}", arg()?), format!("self.{}.as_ref()", field_name))]
+        }
+        "Cow" => vec![(format!("&{}", arg()?), format!("&self.{}", field_name))],
+        "Option" => {
+            vec![(format!("Option<&{}>", arg()?), format!("self.{}.as_ref()", field_name))]
+        }
+        "PathBuf" => vec![("&Path".to_string(), format!("self.{}.as_path()", field_name))],
+        "OsString" => vec![("&OsStr".to_string(), format!("self.{}.as_os_str()", field_name))],
+        "CString" => vec![("&CStr".to_string(), format!("self.{}.as_c_str()", field_name))],
+        _ => return None,
+    };
+    Some(views)
 }
 
 pub(crate) fn get_methods(items: &ast::AssocItemList) -> Vec<ast::Fn> {
@@ -0,0 +1,216 @@
+//! A small builder for LSP tabstop/placeholder snippet syntax (`$1`, `${1:expr}`, `$0`, ...).
+//!
+//! Rather than string-substituting a single `$0`/`${0:...}` marker into
+//! already-serialized syntax, callers record tabstop and placeholder
+//! annotations directly on the [`SyntaxNode`]s they want to make editable,
+//! then call [`Builder::finish`] once to serialize the whole node, assigning
+//! sequential indices and escaping `{`, `}`, `$` in a single pass. This lets
+//! an assist express several independent cursor stops at once, e.g. a
+//! generated function signature with multiple typed-hole arguments.
+
+use stdx::format_to;
+use syntax::{SyntaxNode, TextRange, TextSize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A bare, zero-width tabstop, e.g. `$1`.
+    Tabstop,
+    /// A placeholder whose default text is the annotated range, e.g. `${1:expr}`.
+    Placeholder,
+}
+
+#[derive(Debug)]
+struct Stop {
+    range: TextRange,
+    kind: Kind,
+    is_final: bool,
+}
+
+/// Records tabstop/placeholder annotations against ranges of a [`SyntaxNode`]
+/// and serializes them into a single LSP snippet string.
+///
+/// Non-final stops are numbered `$1`, `$2`, … in the document order of the
+/// ranges they were recorded against, not the order in which they were
+/// added; the stop marked via one of the `final_*` methods (there can only
+/// be one) always renders as `$0`/`${0:...}`, regardless of where it sits
+/// among the others.
+#[derive(Debug, Default)]
+pub(crate) struct Builder {
+    stops: Vec<Stop>,
+}
+
+impl Builder {
+    pub(crate) fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Inserts a zero-width tabstop immediately before `node`.
+    pub(crate) fn tabstop_before(&mut self, node: &SyntaxNode) -> &mut Builder {
+        self.push(TextRange::empty(node.text_range().start()), Kind::Tabstop, false)
+    }
+
+    /// Inserts a zero-width tabstop immediately after `node`.
+    pub(crate) fn tabstop_after(&mut self, node: &SyntaxNode) -> &mut Builder {
+        self.push(TextRange::empty(node.text_range().end()), Kind::Tabstop, false)
+    }
+
+    /// Replaces `node` with a placeholder whose default text is `node`'s
+    /// current text, e.g. turns `expr` into `${1:expr}`.
+    pub(crate) fn placeholder(&mut self, node: &SyntaxNode) -> &mut Builder {
+        self.push(node.text_range(), Kind::Placeholder, false)
+    }
+
+    /// Like [`Builder::tabstop_before`], but this is the final (`$0`) stop.
+    /// A later call to a `final_*` method overrides an earlier one.
+    pub(crate) fn final_tabstop_before(&mut self, node: &SyntaxNode) -> &mut Builder {
+        self.push(TextRange::empty(node.text_range().start()), Kind::Tabstop, true)
+    }
+
+    /// Like [`Builder::placeholder`], but this is the final (`${0:...}`)
+    /// stop. A later call to a `final_*` method overrides an earlier one.
+    pub(crate) fn final_placeholder(&mut self, node: &SyntaxNode) -> &mut Builder {
+        self.push(node.text_range(), Kind::Placeholder, true)
+    }
+
+    fn push(&mut self, range: TextRange, kind: Kind, is_final: bool) -> &mut Builder {
+        if is_final {
+            self.stops.retain(|stop| !stop.is_final);
+        }
+        self.stops.push(Stop { range, kind, is_final });
+        self
+    }
+
+    /// Serializes `node` to a snippet string, splicing in every recorded
+    /// stop and escaping `{`, `}`, `$` in the surrounding text.
+    pub(crate) fn finish(mut self, node: &SyntaxNode) -> String {
+        for stop in &self.stops {
+            assert!(node.text_range().contains_range(stop.range));
+        }
+        self.stops.sort_by_key(|stop| (stop.range.start(), stop.range.end()));
+
+        let base = node.text_range().start();
+        let mut buf = String::with_capacity(node.text().len().into());
+        let mut cursor = base;
+        let mut next_index = 1;
+
+        for stop in &self.stops {
+            push_escaped(&mut buf, node, TextRange::new(cursor, stop.range.start()), base);
+
+            let index = if stop.is_final {
+                0
+            } else {
+                let index = next_index;
+                next_index += 1;
+                index
+            };
+
+            match stop.kind {
+                Kind::Tabstop => format_to!(buf, "${}", index),
+                Kind::Placeholder => {
+                    let mut placeholder = node.text().slice(stop.range - base).to_string();
+                    escape(&mut placeholder);
+                    format_to!(buf, "${{{}:{}}}", index, placeholder);
+                }
+            }
+
+            cursor = stop.range.end();
+        }
+        push_escaped(&mut buf, node, TextRange::new(cursor, node.text_range().end()), base);
+        buf
+    }
+}
+
+fn push_escaped(buf: &mut String, node: &SyntaxNode, range: TextRange, base: TextSize) {
+    let mut text = node.text().slice(range - base).to_string();
+    escape(&mut text);
+    buf.push_str(&text);
+}
+
+fn escape(buf: &mut String) {
+    stdx::replace(buf, '{', r"\{");
+    stdx::replace(buf, '}', r"\}");
+    stdx::replace(buf, '$', r"\$");
+}
+
+#[cfg(test)]
+mod tests {
+    use syntax::{
+        ast::{self, HasArgList},
+        AstNode, SourceFile,
+    };
+
+    use super::Builder;
+
+    /// Parses `text` as the tail expression of a function body.
+    fn expr(text: &str) -> ast::Expr {
+        let source = format!("fn f() {{ {} }}", text);
+        let file = SourceFile::parse(&source).ok().unwrap();
+        let block = file.syntax().descendants().find_map(ast::BlockExpr::cast).unwrap();
+        block.stmt_list().unwrap().tail_expr().unwrap()
+    }
+
+    fn call_args(e: &ast::Expr) -> (ast::Expr, ast::Expr) {
+        let call = match e {
+            ast::Expr::CallExpr(call) => call.clone(),
+            _ => panic!("expected a call expression"),
+        };
+        let mut args = call.arg_list().unwrap().args();
+        (args.next().unwrap(), args.next().unwrap())
+    }
+
+    #[test]
+    fn numbers_stops_by_document_order_not_insertion_order() {
+        let e = expr("foo(a, b)");
+        let (a, b) = call_args(&e);
+
+        let mut builder = Builder::new();
+        // Recorded out of document order (b before a); numbering should
+        // still follow where they sit in the text.
+        builder.tabstop_before(b.syntax());
+        builder.tabstop_before(a.syntax());
+
+        assert_eq!(builder.finish(e.syntax()), "foo($1a, $2b)");
+    }
+
+    #[test]
+    fn placeholder_defaults_to_node_text() {
+        let e = expr("foo(a, b)");
+        let (a, _b) = call_args(&e);
+
+        let mut builder = Builder::new();
+        builder.placeholder(a.syntax());
+
+        assert_eq!(builder.finish(e.syntax()), "foo(${1:a}, b)");
+    }
+
+    #[test]
+    fn final_stop_renders_as_dollar_zero_regardless_of_position() {
+        let e = expr("foo(a, b)");
+        let (a, b) = call_args(&e);
+
+        let mut builder = Builder::new();
+        builder.tabstop_before(a.syntax());
+        builder.final_tabstop_before(b.syntax());
+
+        assert_eq!(builder.finish(e.syntax()), "foo($1a, $0b)");
+    }
+
+    #[test]
+    fn a_later_final_stop_overrides_an_earlier_one() {
+        let e = expr("foo(a, b)");
+        let (a, b) = call_args(&e);
+
+        let mut builder = Builder::new();
+        builder.final_tabstop_before(a.syntax());
+        builder.final_tabstop_before(b.syntax());
+
+        assert_eq!(builder.finish(e.syntax()), "foo(a, $0b)");
+    }
+
+    #[test]
+    fn escape_escapes_snippet_metacharacters() {
+        let mut s = "a{b}c$d".to_string();
+        super::escape(&mut s);
+        assert_eq!(s, r"a\{b\}c\$d");
+    }
+}